@@ -0,0 +1,38 @@
+pub const PATH_MODULES_DIR: &str = "/data/adb/modules";
+
+pub const PROCESS_GRANTED_ROOT: u32 = 1 << 0;
+pub const PROCESS_ON_DENYLIST: u32 = 1 << 1;
+pub const PROCESS_ROOT_IS_KSU: u32 = 1 << 2;
+pub const PROCESS_ROOT_IS_MAGISK: u32 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonSocketAction {
+    Negotiate,
+    PingHeartbeat,
+    RequestLogcatFd,
+    DumpLog,
+    ReadNativeBridge,
+    GetProcessFlags,
+    ReadModules,
+    RequestCompanionSocket,
+    GetModuleDir,
+}
+
+impl TryFrom<u8> for DaemonSocketAction {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => DaemonSocketAction::Negotiate,
+            1 => DaemonSocketAction::PingHeartbeat,
+            2 => DaemonSocketAction::RequestLogcatFd,
+            3 => DaemonSocketAction::DumpLog,
+            4 => DaemonSocketAction::ReadNativeBridge,
+            5 => DaemonSocketAction::GetProcessFlags,
+            6 => DaemonSocketAction::ReadModules,
+            7 => DaemonSocketAction::RequestCompanionSocket,
+            8 => DaemonSocketAction::GetModuleDir,
+            _ => anyhow::bail!("Invalid daemon socket action {value}"),
+        })
+    }
+}
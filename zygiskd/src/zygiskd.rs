@@ -8,7 +8,10 @@ use nix::{
     libc::self,
 };
 use passfd::FdPassingExt;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::fs;
 use std::os::fd::{IntoRawFd, OwnedFd, RawFd};
@@ -20,22 +23,143 @@ use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::Command;
 use nix::poll::{poll, PollFd, PollFlags};
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{fork, ForkResult};
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, ForkResult, Pid};
+use std::time::{Duration, Instant};
 
 struct Module {
     name: String,
     memfd: OwnedFd,
-    companion: Mutex<Option<UnixStream>>,
+    so_modified: Option<std::time::SystemTime>,
+    companion: Mutex<CompanionState>,
+}
+
+// Tracks a module's companion across crashes/respawns.
+#[derive(Default)]
+struct CompanionState {
+    socket: Option<UnixStream>,
+    pid: Option<Pid>,
+    last_spawn: Option<Instant>,
+    consecutive_failures: u32,
 }
 
 struct Context {
     native_bridge: String,
-    modules: Vec<Module>,
+    modules: RwLock<Vec<Module>>,
+    // Bumped on every module list swap so clients can tell a cached index is stale.
+    generation: AtomicU64,
+    // pid -> module name, for the SIGCHLD reaper.
+    companion_pids: Mutex<HashMap<i32, String>>,
+}
+
+// Capped by approximate byte size rather than entry count.
+const LOG_BACKLOG_CAP_BYTES: usize = 64 * 1024;
+
+struct LogEntry {
+    level: i32,
+    tag: String,
+    message: String,
+}
+
+impl LogEntry {
+    fn size(&self) -> usize {
+        self.tag.len() + self.message.len() + std::mem::size_of::<i32>()
+    }
+}
+
+struct LogBacklog {
+    entries: VecDeque<LogEntry>,
+    size_bytes: usize,
+}
+
+impl LogBacklog {
+    fn push(&mut self, level: i32, tag: &str, message: &str) {
+        let entry = LogEntry { level, tag: tag.to_string(), message: message.to_string() };
+        self.size_bytes += entry.size();
+        self.entries.push_back(entry);
+        while self.size_bytes > LOG_BACKLOG_CAP_BYTES {
+            let Some(oldest) = self.entries.pop_front() else { break };
+            self.size_bytes -= oldest.size();
+        }
+    }
+}
+
+// Global rather than on `Context`, so it can be fed by `install_backlog_logger`
+// (called before `Context` exists) and survive a `DumpLog` request seeing
+// early-boot/pre-attach diagnostics.
+static LOG_BACKLOG: Mutex<LogBacklog> = Mutex::new(LogBacklog { entries: VecDeque::new(), size_bytes: 0 });
+
+// Wraps whatever logger is already installed (e.g. logcat output) so the
+// daemon's own `log::info!`/`log::warn!` calls - module load/reload,
+// companion crash/reap, watcher errors - also land in `LOG_BACKLOG`
+// alongside module-forwarded lines, instead of only being visible to
+// whatever already reads the real log sink.
+struct BacklogLogger {
+    inner: &'static dyn log::Log,
+}
+
+impl log::Log for BacklogLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let level = android_log_priority(record.level());
+            let message = record.args().to_string();
+            LOG_BACKLOG.lock().unwrap().push(level, record.target(), &message);
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Android log priorities (see <android/log.h>); module-forwarded entries in
+// the same backlog already use this scheme, so daemon-originated ones need
+// to match it rather than `log::Level`'s own (unrelated) numbering.
+fn android_log_priority(level: log::Level) -> i32 {
+    match level {
+        log::Level::Error => 6,
+        log::Level::Warn => 5,
+        log::Level::Info => 4,
+        log::Level::Debug => 3,
+        log::Level::Trace => 2,
+    }
+}
+
+// Must run before any other `log::` call we want captured. Assumes the real
+// logger (e.g. android_logger) is already installed by the time `entry` runs.
+fn install_backlog_logger() {
+    let inner = log::logger();
+    let max_level = log::max_level();
+    if log::set_boxed_logger(Box::new(BacklogLogger { inner })).is_ok() {
+        log::set_max_level(max_level);
+    }
 }
 
 pub fn entry() -> Result<()> {
+    // Before anything else logs, so early-boot/pre-attach messages survive in
+    // `LOG_BACKLOG` for a later `DumpLog`.
+    install_backlog_logger();
+
     unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+    // Keeps orphaned companions (see `spawn_companion`) as our children instead
+    // of reparenting to init, where we couldn't waitpid them.
+    unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) };
+
+    // Block before spawning any other thread, since new threads inherit the
+    // creating thread's mask; otherwise a thread spawned with it unblocked
+    // could have a companion's exit silently dropped instead of reaped.
+    let mut sigchld_mask = SigSet::empty();
+    sigchld_mask.add(Signal::SIGCHLD);
+    sigchld_mask.thread_block()?;
 
     let arch = get_arch()?;
     log::debug!("Daemon architecture: {arch}");
@@ -45,25 +169,122 @@ pub fn entry() -> Result<()> {
 
     let context = Context {
         native_bridge: utils::get_native_bridge(),
-        modules,
+        modules: RwLock::new(modules),
+        generation: AtomicU64::new(0),
+        companion_pids: Mutex::new(HashMap::new()),
     };
     let context = Arc::new(context);
 
+    log::info!("Watch modules directory");
+    let watcher_context = Arc::clone(&context);
+    let watcher_arch = arch.to_string();
+    thread::spawn(move || {
+        if let Err(e) = watch_modules(&watcher_arch, &watcher_context) {
+            log::warn!("Module watcher stopped: {}\n{}", e, e.backtrace());
+        }
+    });
+
     log::info!("Create socket");
     let listener = create_daemon_socket()?;
 
     log::info!("Handle zygote connections");
-    for stream in listener.incoming() {
-        let stream = stream?;
+    run_connection_loop(listener, context, sigchld_mask)
+}
+
+// Fixed rather than one-per-connection, so an app-spawn storm can't grow the
+// number of request-handling threads without bound. Each `RequestLogcatFd`
+// stream still gets a thread of its own (see `stream_logcat`).
+const CONNECTION_WORKERS: usize = 4;
+
+// Single epoll instance instead of a thread per zygote connection; ready
+// connections are handed to a small fixed worker pool for the (still
+// blocking, one-shot-per-connection) protocol handling.
+fn run_connection_loop(listener: UnixListener, context: Arc<Context>, sigchld_mask: SigSet) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let epoll = Epoll::new(EpollCreateFlags::empty())?;
+    epoll.add(&listener, EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, listener.as_raw_fd() as u64))?;
+
+    // SIGCHLD was already blocked in `entry`; collect it through a signalfd
+    // on this same epoll loop instead of a dedicated signal handler.
+    let mut signal_fd = SignalFd::with_flags(&sigchld_mask, SfdFlags::SFD_NONBLOCK)?;
+    let signal_fd_raw = signal_fd.as_raw_fd();
+    epoll.add(&signal_fd, EpollEvent::new(EpollFlags::EPOLLIN, signal_fd_raw as u64))?;
+
+    let connections: Arc<Mutex<HashMap<RawFd, UnixStream>>> = Arc::new(Mutex::new(HashMap::new()));
+    let (job_tx, job_rx) = mpsc::channel::<UnixStream>();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    for _ in 0..CONNECTION_WORKERS {
+        let job_rx = Arc::clone(&job_rx);
         let context = Arc::clone(&context);
-        thread::spawn(move || {
-            if let Err(e) = handle_daemon_action(stream, &context) {
-                log::warn!("Error handling daemon action: {}\n{}", e, e.backtrace());
+        thread::spawn(move || connection_worker(job_rx, context));
+    }
+
+    let listener_fd = listener.as_raw_fd();
+    let mut events = [EpollEvent::empty(); 64];
+    loop {
+        let n = epoll.wait(&mut events, EpollTimeout::NONE)?;
+        for event in &events[..n] {
+            let fd = event.data() as RawFd;
+            if fd == listener_fd {
+                accept_pending(&listener, &epoll, &connections)?;
+                continue;
+            }
+            if fd == signal_fd_raw {
+                while matches!(signal_fd.read_signal(), Ok(Some(_))) {}
+                reap_companions(&context);
+                continue;
             }
-        });
+            let stream = connections.lock().unwrap().remove(&fd);
+            if let Some(stream) = stream {
+                let _ = epoll.delete(&stream);
+                job_tx.send(stream).ok();
+            }
+        }
     }
+}
 
-    Ok(())
+// Edge-triggered listener readiness means all pending connections must be
+// drained in one go, or later arrivals would never wake epoll again.
+fn accept_pending(
+    listener: &UnixListener,
+    epoll: &Epoll,
+    connections: &Mutex<HashMap<RawFd, UnixStream>>,
+) -> Result<()> {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(true)?;
+                let fd = stream.as_raw_fd();
+                epoll.add(&stream, EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, fd as u64))?;
+                connections.lock().unwrap().insert(fd, stream);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Caps how long a worker can be stuck on a stalled client.
+const WORKER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn connection_worker(job_rx: Arc<Mutex<mpsc::Receiver<UnixStream>>>, context: Arc<Context>) {
+    loop {
+        let stream = job_rx.lock().unwrap().recv();
+        let Ok(mut stream) = stream else {
+            return;
+        };
+        // The protocol is blocking/synchronous per connection; epoll only
+        // multiplexes *which* connection is ready, not the read calls within it.
+        if stream.set_nonblocking(false).is_err() {
+            continue;
+        }
+        if stream.set_read_timeout(Some(WORKER_READ_TIMEOUT)).is_err() {
+            continue;
+        }
+        if let Err(e) = handle_daemon_action(stream, Arc::clone(&context)) {
+            log::warn!("Error handling daemon action: {}\n{}", e, e.backtrace());
+        }
+    }
 }
 
 fn get_arch() -> Result<&'static str> {
@@ -102,14 +323,130 @@ fn load_modules(arch: &str) -> Result<Vec<Module>> {
                 continue;
             }
         };
-        let companion = Mutex::new(None);
-        let module = Module { name, memfd: fd, companion };
+        let so_modified = fs::metadata(&so_path).and_then(|m| m.modified()).ok();
+        let companion = Mutex::new(CompanionState::default());
+        let module = Module { name, memfd: fd, so_modified, companion };
         modules.push(module);
     }
 
     Ok(modules)
 }
 
+// Keeps `context.modules` in sync with the modules directory, so
+// enabling/disabling/installing a module doesn't require a daemon restart.
+fn watch_modules(arch: &str, context: &Context) -> Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK)?;
+    rewatch_modules(&inotify)?;
+
+    // Coalesce bursts of events (e.g. an unzip of a new module) into a single
+    // rescan instead of reloading once per inotify event.
+    let mut pfds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+    loop {
+        poll(&mut pfds, -1)?;
+        // Drain whatever is queued before reacting.
+        while inotify.read_events().is_ok() {}
+
+        rewatch_modules(&inotify)?;
+        if let Err(e) = reload_modules(arch, context) {
+            log::warn!("Failed to reload modules: {}\n{}", e, e.backtrace());
+        }
+    }
+}
+
+// Safe to call repeatedly: watching an already-watched path is a no-op.
+fn rewatch_modules(inotify: &Inotify) -> Result<()> {
+    let flags = AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_CLOSE_WRITE;
+
+    inotify.add_watch(constants::PATH_MODULES_DIR, flags)?;
+
+    let dir = match fs::read_dir(constants::PATH_MODULES_DIR) {
+        Ok(dir) => dir,
+        Err(_) => return Ok(()),
+    };
+    for entry_result in dir.into_iter() {
+        let entry = entry_result?;
+        let path = entry.path();
+        if inotify.add_watch(&path, flags).is_err() {
+            continue;
+        }
+        let zygisk_dir = path.join("zygisk");
+        let _ = inotify.add_watch(&zygisk_dir, flags);
+    }
+
+    Ok(())
+}
+
+// Rescans `PATH_MODULES_DIR` and swaps `context.modules` for the result,
+// reusing memfds for modules whose `.so` hasn't changed and carrying their
+// companion socket across the reload.
+fn reload_modules(arch: &str, context: &Context) -> Result<()> {
+    let dir = match fs::read_dir(constants::PATH_MODULES_DIR) {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::warn!("Failed reading modules directory: {}", e);
+            return Ok(());
+        }
+    };
+
+    // Scan fully before touching the live list, so an error partway through
+    // doesn't wipe out modules that were working.
+    let mut scanned = Vec::new();
+    for entry_result in dir.into_iter() {
+        let entry = entry_result?;
+        let name = entry.file_name().into_string().unwrap();
+        let so_path = entry.path().join(format!("zygisk/{arch}.so"));
+        let disabled = entry.path().join("disable");
+        if !so_path.exists() || disabled.exists() {
+            continue;
+        }
+        let so_modified = fs::metadata(&so_path).and_then(|m| m.modified()).ok();
+        scanned.push((name, so_path, so_modified));
+    }
+
+    let mut modules = context.modules.write().unwrap();
+    let mut stale: HashMap<String, Module> =
+        modules.drain(..).map(|m| (m.name.clone(), m)).collect();
+
+    let mut next = Vec::new();
+    for (name, so_path, so_modified) in scanned {
+        if let Some(existing) = stale.remove(&name) {
+            if existing.so_modified == so_modified {
+                next.push(existing);
+                continue;
+            }
+            log::info!("  Reloading module `{name}`...");
+        } else {
+            log::info!("  Loading module `{name}`...");
+        }
+
+        let fd = match create_library_fd(&so_path) {
+            Ok(fd) => fd,
+            Err(e) => {
+                log::warn!("  Failed to create memfd for `{name}`: {e}");
+                continue;
+            }
+        };
+        next.push(Module { name, memfd: fd, so_modified, companion: Mutex::new(CompanionState::default()) });
+    }
+
+    for (name, module) in stale {
+        log::info!("  Module `{name}` removed");
+        if let Some(pid) = module.companion.lock().unwrap().pid {
+            context.companion_pids.lock().unwrap().remove(&pid.as_raw());
+        }
+    }
+
+    *modules = next;
+    let generation = context.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    log::debug!("Module list reloaded, generation {generation}");
+
+    Ok(())
+}
+
 #[cfg(debug_assertions)]
 fn create_library_fd(so_path: &PathBuf) -> Result<OwnedFd> {
     Ok(OwnedFd::from(fs::File::open(so_path)?))
@@ -143,67 +480,458 @@ fn create_daemon_socket() -> Result<UnixListener> {
     Ok(listener)
 }
 
-fn spawn_companion(name: &str, fd: &RawFd) -> Result<Option<UnixStream>> {
+// Scaled by consecutive failures so a crash-looping companion can't
+// fork-bomb the device. Capped at 64s.
+fn companion_backoff(consecutive_failures: u32) -> Duration {
+    Duration::from_secs(1u64 << consecutive_failures.min(6))
+}
+
+// Reaps every exited/killed child and clears its module's companion state
+// so the next `RequestCompanionSocket` respawns it.
+fn reap_companions(context: &Context) {
+    loop {
+        let status = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(status) => status,
+            Err(_) => break,
+        };
+        let (pid, reason) = match status {
+            WaitStatus::Exited(pid, code) => (pid, format!("exited with code {code}")),
+            WaitStatus::Signaled(pid, sig, _) => (pid, format!("was killed by signal {sig:?}")),
+            WaitStatus::StillAlive => break,
+            _ => continue,
+        };
+
+        let name = context.companion_pids.lock().unwrap().remove(&pid.as_raw());
+        let Some(name) = name else { continue };
+        let modules = context.modules.read().unwrap();
+        if let Some(module) = modules.iter().find(|m| m.name == name) {
+            log::warn!("Companion for module `{name}` {reason}");
+            let mut companion = module.companion.lock().unwrap();
+            companion.socket = None;
+            companion.pid = None;
+            companion.consecutive_failures += 1;
+        }
+    }
+}
+
+fn spawn_companion(name: &str, fd: &RawFd) -> Result<(Option<UnixStream>, Option<Pid>)> {
     let (mut daemon, companion) = UnixStream::pair()?;
     // Remove FD_CLOEXEC flag
     fcntl(companion.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty()))?;
 
     let process = std::env::args().next().unwrap();
     let nice_name = process.split('/').last().unwrap();
+    let sandbox = load_companion_sandbox(name);
+    // Relays the grandchild's pid back, since the intermediate fork's exit
+    // code can't carry a full pid.
+    let (pid_rx, pid_tx) = nix::unistd::pipe()?;
 
-    match unsafe { fork()? } {
-        ForkResult::Parent { child, ..} => {
-            if let Ok(WaitStatus::Exited(.., code)) = waitpid(child, None) {
-                ensure!(code == 0, format!("process exited with {code}"));
-            } else {
-                bail!("process exited abnormally");
-            }
+    let companion_pid = match unsafe { fork()? } {
+        // Don't `waitpid` the intermediate pid here: `reap_companions` already
+        // reaps every exited child via SIGCHLD, and a second direct wait here
+        // would race it for the same pid (whichever loses gets ECHILD). The
+        // pid pipe closing tells us everything we need - whether the
+        // grandchild was spawned - without needing this process's exit status.
+        ForkResult::Parent { .. } => {
+            drop(pid_tx);
+
+            let mut buf = [0u8; 4];
+            let pid = match nix::unistd::read(pid_rx.as_raw_fd(), &mut buf) {
+                Ok(4) => Some(Pid::from_raw(i32::from_le_bytes(buf))),
+                _ => None,
+            };
+            drop(pid_rx);
+            pid
         }
         ForkResult::Child => {
-            Command::new(&process)
+            drop(pid_rx);
+
+            if let Some(sandbox) = &sandbox {
+                if sandbox.unshare_namespaces {
+                    // CLONE_NEWPID only puts *future children* of the calling
+                    // process into the new namespace, not the caller itself -
+                    // unsharing from inside the grandchild's own `pre_exec`
+                    // (i.e. after the last fork) would be too late to isolate
+                    // the companion. Unshare here instead, one level up, so
+                    // `Command::spawn`'s own fork below lands the companion in
+                    // the new namespace as its PID 1.
+                    if let Err(e) = unshare_companion_namespaces() {
+                        log::error!("  Failed to unshare namespaces for companion `{name}`: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            let mut command = Command::new(&process);
+            command
                 .arg0(format!("{}-{}", nice_name, name))
                 .arg("companion")
-                .arg(format!("{}", companion.as_raw_fd()))
-                .spawn()?;
+                .arg(format!("{}", companion.as_raw_fd()));
+            if let Some(sandbox) = sandbox {
+                // `Command::spawn` still needs to fork+exec, so the seccomp
+                // filter can't go on this process; install it in the
+                // grandchild right before its own exec.
+                unsafe {
+                    command.pre_exec(move || {
+                        apply_companion_seccomp(&sandbox)
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+                    });
+                }
+            }
+
+            match command.spawn() {
+                Ok(child) => {
+                    let _ = nix::unistd::write(&pid_tx, &(child.id() as i32).to_le_bytes());
+                }
+                Err(e) => log::error!("  Failed to exec companion for `{name}`: {e}"),
+            }
             drop(companion);
+            drop(pid_tx);
 
             std::process::exit(0);
         }
-    }
+    };
 
     daemon.write_string(name)?;
     daemon.send_fd(*fd)?;
     match daemon.read_u8()? {
-        0 => Ok(None),
-        1 => Ok(Some(daemon)),
+        0 => Ok((None, None)),
+        1 => Ok((Some(daemon), companion_pid)),
         _ => bail!("Invalid companion response"),
     }
 }
 
-fn handle_daemon_action(mut stream: UnixStream, context: &Context) -> Result<()> {
-    let action = stream.read_u8()?;
-    let action = DaemonSocketAction::try_from(action)?;
-    log::trace!("New daemon action {:?}", action);
+// Opt-in hardening for a module's companion, read from
+// `<module>/companion.seccomp`. Absence of the file means "run unsandboxed".
+struct CompanionSandbox {
+    allowed_syscalls: Vec<i64>,
+    unshare_namespaces: bool,
+}
+
+fn load_companion_sandbox(name: &str) -> Option<CompanionSandbox> {
+    let path = format!("{}/{name}/companion.seccomp", constants::PATH_MODULES_DIR);
+    let contents = fs::read_to_string(path).ok()?;
+
+    // The companion still has to complete its own exec (and later exit)
+    // under the filter, so a policy file only needs to list syscalls its
+    // actual work requires.
+    let mut allowed_syscalls = vec![libc::SYS_execve, libc::SYS_rt_sigreturn, libc::SYS_exit, libc::SYS_exit_group];
+    let mut unshare_namespaces = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "unshare_namespaces" {
+            unshare_namespaces = true;
+            continue;
+        }
+        match syscall_number(line) {
+            Some(nr) => allowed_syscalls.push(nr),
+            None => log::warn!("  Unknown syscall `{line}` in seccomp policy for `{name}`, ignoring"),
+        }
+    }
+
+    Some(CompanionSandbox { allowed_syscalls, unshare_namespaces })
+}
+
+// Must run one level above the companion's own exec (see the call site in
+// `spawn_companion`), since CLONE_NEWPID only takes effect for children
+// forked after this call, not for the calling process itself.
+fn unshare_companion_namespaces() -> Result<()> {
+    nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWNS | nix::sched::CloneFlags::CLONE_NEWPID)?;
+    Ok(())
+}
+
+// Runs in the forked child, before `exec`. Must never return `Ok` while
+// leaving the process unsandboxed.
+fn apply_companion_seccomp(sandbox: &CompanionSandbox) -> Result<()> {
+    // Seccomp-bpf refuses to install unless no_new_privs is set first.
+    let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    ensure!(ret == 0, "prctl(PR_SET_NO_NEW_PRIVS) failed: {}", std::io::Error::last_os_error());
+
+    install_seccomp_filter(&sandbox.allowed_syscalls)
+}
+
+const BPF_LD: u16 = 0x00;
+const BPF_W: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_JMP: u16 = 0x05;
+const BPF_JEQ: u16 = 0x10;
+const BPF_K: u16 = 0x00;
+const BPF_RET: u16 = 0x06;
+
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO_EPERM: u32 = 0x0005_0000 | (libc::EPERM as u32 & 0xffff);
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+
+// Offsets of `struct seccomp_data` fields (see <linux/seccomp.h>).
+const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+fn bpf_stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter { code, jt: 0, jf: 0, k }
+}
+
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+// Installs a seccomp-bpf filter allowing only `allowed_syscalls`. The
+// prologue checks `seccomp_data.arch` first and kills the process on
+// mismatch, defeating 32/64-bit arch-smuggling.
+fn install_seccomp_filter(allowed_syscalls: &[i64]) -> Result<()> {
+    let mut program = vec![
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH_OFFSET),
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, audit_arch(), 1, 0),
+        bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR_OFFSET),
+    ];
+
+    for (i, nr) in allowed_syscalls.iter().enumerate() {
+        let jt = (allowed_syscalls.len() - i) as u8;
+        program.push(bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, jt, 0));
+    }
+    program.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ERRNO_EPERM));
+    program.push(bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+
+    let prog = SockFprog { len: program.len() as libc::c_ushort, filter: program.as_ptr() };
+    let ret = unsafe {
+        libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog as *const SockFprog)
+    };
+    ensure!(ret == 0, "prctl(PR_SET_SECCOMP) failed: {}", std::io::Error::last_os_error());
+    Ok(())
+}
+
+#[cfg(target_arch = "aarch64")]
+fn audit_arch() -> u32 { libc::AUDIT_ARCH_AARCH64 as u32 }
+#[cfg(target_arch = "arm")]
+fn audit_arch() -> u32 { libc::AUDIT_ARCH_ARM as u32 }
+#[cfg(target_arch = "x86_64")]
+fn audit_arch() -> u32 { libc::AUDIT_ARCH_X86_64 as u32 }
+#[cfg(target_arch = "x86")]
+fn audit_arch() -> u32 { libc::AUDIT_ARCH_I386 as u32 }
+
+// Small curated allowlist of syscalls a companion plausibly needs: file and
+// socket I/O, memory management and the bits glibc/musl need just to run.
+fn syscall_number(name: &str) -> Option<i64> {
+    Some(match name {
+        "read" => libc::SYS_read,
+        "write" => libc::SYS_write,
+        "close" => libc::SYS_close,
+        "mmap" => libc::SYS_mmap,
+        "munmap" => libc::SYS_munmap,
+        "mprotect" => libc::SYS_mprotect,
+        "brk" => libc::SYS_brk,
+        "futex" => libc::SYS_futex,
+        "socket" => libc::SYS_socket,
+        "connect" => libc::SYS_connect,
+        "accept" => libc::SYS_accept,
+        "accept4" => libc::SYS_accept4,
+        "bind" => libc::SYS_bind,
+        "listen" => libc::SYS_listen,
+        "sendto" => libc::SYS_sendto,
+        "recvfrom" => libc::SYS_recvfrom,
+        "sendmsg" => libc::SYS_sendmsg,
+        "recvmsg" => libc::SYS_recvmsg,
+        "poll" => libc::SYS_poll,
+        "ppoll" => libc::SYS_ppoll,
+        "epoll_wait" => libc::SYS_epoll_wait,
+        "epoll_ctl" => libc::SYS_epoll_ctl,
+        "clock_gettime" => libc::SYS_clock_gettime,
+        "nanosleep" => libc::SYS_nanosleep,
+        "getpid" => libc::SYS_getpid,
+        "gettid" => libc::SYS_gettid,
+        "openat" => libc::SYS_openat,
+        "fstat" => libc::SYS_fstat,
+        "lseek" => libc::SYS_lseek,
+        "ioctl" => libc::SYS_ioctl,
+        "rt_sigreturn" => libc::SYS_rt_sigreturn,
+        "exit" => libc::SYS_exit,
+        "exit_group" => libc::SYS_exit_group,
+        "execve" => libc::SYS_execve,
+        "execveat" => libc::SYS_execveat,
+        _ => return None,
+    })
+}
+
+// Supported protocol versions, inclusive.
+const PROTOCOL_VERSION_RANGE: std::ops::RangeInclusive<u8> = 1..=1;
+
+const MAX_BODY_LEN: u32 = 1024 * 1024;
+
+#[repr(u8)]
+enum RpcStatus {
+    Ok = 0,
+    UnsupportedVersion = 1,
+    BadRequest = 2,
+    InternalError = 3,
+}
+
+// `RequestCompanionSocket`'s own reply, sent after the `RpcStatus::Ok` byte:
+// a leftover inner protocol from before `RpcStatus` existed. Values
+// deliberately aren't `RpcStatus` variants - this byte means something
+// different (whether/how a companion fd follows), not request framing.
+const COMPANION_SOCKET_NONE: u8 = 0;
+const COMPANION_SOCKET_BACKING_OFF: u8 = 2;
+
+// Reads action fields out of the body bytes declared by `body_len`, rather
+// than off the live stream, so the full declared length is always consumed
+// up front - a short or malformed body fails locally instead of leaving the
+// stream half-read for whatever request comes next.
+struct BodyReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        ensure!(self.pos + 1 <= self.buf.len(), "body too short for u8");
+        let v = self.buf[self.pos];
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        ensure!(self.pos + 4 <= self.buf.len(), "body too short for u32");
+        let v = u32::from_le_bytes(self.buf[self.pos..self.pos + 4].try_into().unwrap());
+        self.pos += 4;
+        Ok(v)
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        ensure!(self.pos + 8 <= self.buf.len(), "body too short for u64");
+        let v = u64::from_le_bytes(self.buf[self.pos..self.pos + 8].try_into().unwrap());
+        self.pos += 8;
+        Ok(v)
+    }
+
+    fn read_usize(&mut self) -> Result<usize> {
+        Ok(self.read_u64()? as usize)
+    }
+}
+
+fn stream_logcat(mut stream: UnixStream) {
+    // This connection is long-lived by design; the worker pool's read
+    // timeout would otherwise fire between log lines.
+    let _ = stream.set_read_timeout(None);
+    loop {
+        let level = match stream.read_u8() {
+            Ok(level) => level,
+            Err(_) => return,
+        };
+        let tag = match stream.read_string() {
+            Ok(tag) => tag,
+            Err(e) => {
+                log::warn!("Error reading logcat tag: {e}");
+                return;
+            }
+        };
+        let message = match stream.read_string() {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("Error reading logcat message: {e}");
+                return;
+            }
+        };
+        LOG_BACKLOG.lock().unwrap().push(level as i32, &tag, &message);
+        if let Err(e) = utils::log_raw(level as i32, &tag, &message) {
+            log::warn!("Error logging module message: {e}");
+            return;
+        }
+    }
+}
+
+fn handle_daemon_action(mut stream: UnixStream, context: Arc<Context>) -> Result<()> {
+    // Framed as: 1-byte protocol version, u32 LE body length, then the body.
+    // Each arm writes its own status byte once it knows whether it
+    // succeeded, rather than promising `Ok` up front.
+    let version = stream.read_u8()?;
+    if !PROTOCOL_VERSION_RANGE.contains(&version) {
+        stream.write_u8(RpcStatus::UnsupportedVersion as u8)?;
+        bail!("Unsupported protocol version {version}, daemon supports {:?}", PROTOCOL_VERSION_RANGE);
+    }
+    let body_len = stream.read_u32()?;
+    if body_len > MAX_BODY_LEN {
+        stream.write_u8(RpcStatus::BadRequest as u8)?;
+        bail!("Declared body length {body_len} exceeds max of {MAX_BODY_LEN}");
+    }
+
+    // Always read exactly the declared length off the wire before doing
+    // anything else with it, so a handler bailing partway through parsing
+    // never leaves unread body bytes for the next request on this stream.
+    let mut body_buf = vec![0u8; body_len as usize];
+    std::io::Read::read_exact(&mut stream, &mut body_buf)?;
+    let mut body = BodyReader::new(&body_buf);
+
+    let action = match body.read_u8() {
+        Ok(action) => action,
+        Err(e) => {
+            stream.write_u8(RpcStatus::BadRequest as u8)?;
+            return Err(e);
+        }
+    };
+    let action = match DaemonSocketAction::try_from(action) {
+        Ok(action) => action,
+        Err(e) => {
+            stream.write_u8(RpcStatus::BadRequest as u8)?;
+            return Err(e);
+        }
+    };
+    log::trace!("New daemon action {:?} (body {body_len} bytes)", action);
+
     match action {
+        DaemonSocketAction::Negotiate => {
+            stream.write_u8(RpcStatus::Ok as u8)?;
+            stream.write_u8(*PROTOCOL_VERSION_RANGE.start())?;
+            stream.write_u8(*PROTOCOL_VERSION_RANGE.end())?;
+            stream.write_u64(context.generation.load(Ordering::SeqCst))?;
+        }
         DaemonSocketAction::PingHeartbeat => {
-            // Do nothing
+            stream.write_u8(RpcStatus::Ok as u8)?;
         }
         DaemonSocketAction::RequestLogcatFd => {
-            loop {
-                let level = match stream.read_u8() {
-                    Ok(level) => level,
-                    Err(_) => break,
-                };
-                let tag = stream.read_string()?;
-                let message = stream.read_string()?;
-                utils::log_raw(level as i32, &tag, &message)?;
+            stream.write_u8(RpcStatus::Ok as u8)?;
+            // Long-lived; handling it inline would tie up a worker for good.
+            thread::spawn(move || stream_logcat(stream));
+        }
+        DaemonSocketAction::DumpLog => {
+            stream.write_u8(RpcStatus::Ok as u8)?;
+            let backlog = LOG_BACKLOG.lock().unwrap();
+            stream.write_usize(backlog.entries.len())?;
+            for entry in backlog.entries.iter() {
+                stream.write_u32(entry.level as u32)?;
+                stream.write_string(&entry.tag)?;
+                stream.write_string(&entry.message)?;
             }
         }
         DaemonSocketAction::ReadNativeBridge => {
+            stream.write_u8(RpcStatus::Ok as u8)?;
             stream.write_string(&context.native_bridge)?;
         }
         DaemonSocketAction::GetProcessFlags => {
-            let uid = stream.read_u32()? as i32;
+            let Ok(uid) = body.read_u32().map(|uid| uid as i32) else {
+                stream.write_u8(RpcStatus::BadRequest as u8)?;
+                bail!("GetProcessFlags: body too short");
+            };
             let mut flags = 0u32;
             if root_impl::uid_on_allowlist(uid) {
                 flags |= constants::PROCESS_GRANTED_ROOT;
@@ -217,60 +945,123 @@ fn handle_daemon_action(mut stream: UnixStream, context: &Context) -> Result<()>
                 _ => unreachable!(),
             }
             // TODO: PROCESS_IS_SYSUI?
+            stream.write_u8(RpcStatus::Ok as u8)?;
             stream.write_u32(flags)?;
         }
         DaemonSocketAction::ReadModules => {
-            stream.write_usize(context.modules.len())?;
-            for module in context.modules.iter() {
+            let modules = context.modules.read().unwrap();
+            stream.write_u8(RpcStatus::Ok as u8)?;
+            stream.write_u64(context.generation.load(Ordering::SeqCst))?;
+            stream.write_usize(modules.len())?;
+            for module in modules.iter() {
                 stream.write_string(&module.name)?;
                 stream.send_fd(module.memfd.as_raw_fd())?;
             }
         }
         DaemonSocketAction::RequestCompanionSocket => {
-            let index = stream.read_usize()?;
-            let module = &context.modules[index];
+            let (Ok(index), Ok(want_generation)) = (body.read_usize(), body.read_u64()) else {
+                stream.write_u8(RpcStatus::BadRequest as u8)?;
+                bail!("RequestCompanionSocket: body too short");
+            };
+            let current_generation = context.generation.load(Ordering::SeqCst);
+            if want_generation != current_generation {
+                // The client's cached index was built from a module list that
+                // has since been reloaded; `reload_modules` doesn't guarantee
+                // stable ordering, so handing it out anyway could hand back a
+                // different module's companion.
+                log::warn!(
+                    "RequestCompanionSocket: stale generation {want_generation}, current {current_generation}"
+                );
+                stream.write_u8(RpcStatus::BadRequest as u8)?;
+                return Ok(());
+            }
+            let modules = context.modules.read().unwrap();
+            let Some(module) = modules.get(index) else {
+                // A stale client-cached index just gets "no companion".
+                log::warn!("RequestCompanionSocket: index {index} out of bounds ({} modules)", modules.len());
+                stream.write_u8(RpcStatus::Ok as u8)?;
+                stream.write_u8(COMPANION_SOCKET_NONE)?;
+                return Ok(());
+            };
+            stream.write_u8(RpcStatus::Ok as u8)?;
             let name = &module.name;
             let fd = &module.memfd;
             let mut companion = module.companion.lock().unwrap();
-            if let Some(sock) = companion.as_ref() {
-                let mut pfds = [PollFd::new(sock.as_raw_fd(), PollFlags::empty())];
-                poll(&mut pfds, 0)?;
-                if !pfds[0].revents().unwrap().is_empty() {
-                    log::error!("poll companion for module `{}` crashed", name);
-                    companion.take();
+
+            // `None` means "not yet spawned" or "crashed and reaped" either way.
+            if companion.socket.is_none() {
+                let backoff = companion_backoff(companion.consecutive_failures);
+                let elapsed = companion.last_spawn.map(|t| t.elapsed()).unwrap_or(backoff);
+                if elapsed < backoff {
+                    log::trace!("  companion for `{name}` unavailable, backing off for {:?}", backoff - elapsed);
+                    stream.write_u8(COMPANION_SOCKET_BACKING_OFF)?;
+                    return Ok(());
                 }
-            }
-            if companion.as_ref().is_none() {
-                match spawn_companion(&name, &fd.as_raw_fd()) {
-                    Ok(c) => {
+
+                companion.last_spawn = Some(Instant::now());
+                match spawn_companion(name, &fd.as_raw_fd()) {
+                    Ok((Some(sock), pid)) => {
                         log::trace!("  spawned companion for `{name}`");
-                        *companion = c;
-                    },
+                        if let Some(pid) = pid {
+                            context.companion_pids.lock().unwrap().insert(pid.as_raw(), name.clone());
+                        }
+                        companion.socket = Some(sock);
+                        companion.pid = pid;
+                        companion.consecutive_failures = 0;
+                    }
+                    Ok((None, _)) => {
+                        companion.consecutive_failures += 1;
+                    }
                     Err(e) => {
                         log::warn!("  Failed to spawn companion for `{name}`: {e}");
+                        companion.consecutive_failures += 1;
                     }
                 };
             }
-            match companion.as_ref() {
+            match companion.socket.as_ref() {
                 Some(sock) => {
                     if let Err(_) = sock.send_fd(stream.as_raw_fd()) {
                         log::error!("Companion socket of module `{}` missing", module.name);
 
-                        stream.write_u8(0)?;
+                        stream.write_u8(COMPANION_SOCKET_NONE)?;
                     }
                     // Ok: Send by companion
                 }
                 None => {
-                    stream.write_u8(0)?;
+                    stream.write_u8(COMPANION_SOCKET_NONE)?;
                 }
             }
         }
         DaemonSocketAction::GetModuleDir => {
-            let index = stream.read_usize()?;
-            let module = &context.modules[index];
-            let dir = format!("{}/{}", constants::PATH_MODULES_DIR, module.name);
-            let dir = fs::File::open(dir)?;
-            stream.send_fd(dir.as_raw_fd())?;
+            let (Ok(index), Ok(want_generation)) = (body.read_usize(), body.read_u64()) else {
+                stream.write_u8(RpcStatus::BadRequest as u8)?;
+                bail!("GetModuleDir: body too short");
+            };
+            let current_generation = context.generation.load(Ordering::SeqCst);
+            if want_generation != current_generation {
+                log::warn!(
+                    "GetModuleDir: stale generation {want_generation}, current {current_generation}"
+                );
+                stream.write_u8(RpcStatus::BadRequest as u8)?;
+                return Ok(());
+            }
+            let modules = context.modules.read().unwrap();
+            let Some(module) = modules.get(index) else {
+                log::warn!("GetModuleDir: index {index} out of bounds ({} modules)", modules.len());
+                stream.write_u8(RpcStatus::BadRequest as u8)?;
+                return Ok(());
+            };
+            let dir_path = format!("{}/{}", constants::PATH_MODULES_DIR, module.name);
+            match fs::File::open(&dir_path) {
+                Ok(dir) => {
+                    stream.write_u8(RpcStatus::Ok as u8)?;
+                    stream.send_fd(dir.as_raw_fd())?;
+                }
+                Err(e) => {
+                    log::warn!("GetModuleDir: failed to open `{dir_path}`: {e}");
+                    stream.write_u8(RpcStatus::InternalError as u8)?;
+                }
+            }
         }
     }
     Ok(())